@@ -12,35 +12,120 @@ use syn::{parse::Parse, spanned::Spanned, token::Comma, Ident, Path};
 
 /// Generates the function that is called by the python interpreter to initialize the native
 /// module
-pub fn py_init(fnname: &Ident, name: &Ident, doc: syn::LitStr) -> TokenStream {
+pub fn py_init(fnname: &Ident, name: &Ident, doc: syn::LitStr, multi_phase: bool) -> TokenStream {
     let cb_name = Ident::new(&format!("PyInit_{}", name), Span::call_site());
     assert!(doc.value().ends_with('\0'));
 
-    quote! {
-        #[no_mangle]
-        #[allow(non_snake_case)]
-        /// This autogenerated function is called by the python interpreter when importing
-        /// the module.
-        pub unsafe extern "C" fn #cb_name() -> *mut pyo3::ffi::PyObject {
-            use pyo3::derive_utils::ModuleDef;
-            static NAME: &str = concat!(stringify!(#name), "\0");
-            static DOC: &str = #doc;
-            static MODULE_DEF: ModuleDef = unsafe { ModuleDef::new(NAME, DOC) };
-
-            pyo3::callback::handle_panic(|_py| { MODULE_DEF.make_module(_py, #fnname) })
+    if multi_phase {
+        // PEP 489 multi-phase initialization: `PyInit_<name>` only returns an uninitialized
+        // `PyModuleDef` built via `PyModuleDef_Init`; the interpreter is then responsible for
+        // creating the module object and invoking the `Py_mod_exec` slot, which runs the
+        // user's `#[pymodule]` body.
+        //
+        // The `PyModuleDef` is built by hand here rather than through `ModuleDef::new` (used
+        // by the single-phase path below) for two reasons: that helper has no way to attach
+        // `m_slots`, and it fixes `m_size` at `-1`, which tells CPython the module keeps global
+        // C state and is therefore unsafe to re-`exec` into a fresh sub-interpreter. `m_size`
+        // must be `>= 0` for a multi-phase module so that guarantee actually holds.
+        quote! {
+            #[no_mangle]
+            #[allow(non_snake_case)]
+            /// This autogenerated function is called by the python interpreter when importing
+            /// the module.
+            pub unsafe extern "C" fn #cb_name() -> *mut pyo3::ffi::PyObject {
+                unsafe extern "C" fn exec(module: *mut pyo3::ffi::PyObject) -> std::os::raw::c_int {
+                    pyo3::callback::handle_panic(|py| {
+                        let module: &pyo3::types::PyModule = py.from_borrowed_ptr(module);
+                        #fnname(py, module)?;
+                        Ok(0)
+                    })
+                }
+
+                static mut SLOTS: [pyo3::ffi::PyModuleDef_Slot; 2] = [
+                    pyo3::ffi::PyModuleDef_Slot {
+                        slot: pyo3::ffi::Py_mod_exec,
+                        value: exec as *mut std::os::raw::c_void,
+                    },
+                    pyo3::ffi::PyModuleDef_Slot {
+                        slot: 0,
+                        value: std::ptr::null_mut(),
+                    },
+                ];
+
+                static NAME: &str = concat!(stringify!(#name), "\0");
+                static DOC: &str = #doc;
+                static mut MODULE_DEF: pyo3::ffi::PyModuleDef = pyo3::ffi::PyModuleDef {
+                    m_base: pyo3::ffi::PyModuleDef_HEAD_INIT,
+                    m_name: NAME.as_ptr() as *const std::os::raw::c_char,
+                    m_doc: DOC.as_ptr() as *const std::os::raw::c_char,
+                    m_size: 0,
+                    m_methods: std::ptr::null_mut(),
+                    m_slots: SLOTS.as_mut_ptr(),
+                    m_traverse: None,
+                    m_clear: None,
+                    m_free: None,
+                };
+
+                pyo3::ffi::PyModuleDef_Init(&mut MODULE_DEF)
+            }
+        }
+    } else {
+        quote! {
+            #[no_mangle]
+            #[allow(non_snake_case)]
+            /// This autogenerated function is called by the python interpreter when importing
+            /// the module.
+            pub unsafe extern "C" fn #cb_name() -> *mut pyo3::ffi::PyObject {
+                use pyo3::derive_utils::ModuleDef;
+                static NAME: &str = concat!(stringify!(#name), "\0");
+                static DOC: &str = #doc;
+                static MODULE_DEF: ModuleDef = unsafe { ModuleDef::new(NAME, DOC) };
+
+                pyo3::callback::handle_panic(|_py| { MODULE_DEF.make_module(_py, #fnname) })
+            }
         }
     }
 }
 
-/// Finds and takes care of the #[pyfn(...)] in `#[pymodule]`
-pub fn process_functions_in_module(func: &mut syn::ItemFn) -> syn::Result<()> {
+/// Finds and takes care of the #[pyfn(...)] and #[pyo3(submodule)] items in `#[pymodule]`
+///
+/// `module_name` is the dotted Python path of the module being processed (e.g. `parent` or
+/// `parent.child`), used to register any nested submodules under the right `sys.modules` key.
+///
+/// When `auto_all` is set, the names registered through `#[pyfn]`, `#[pyo3(submodule)]`,
+/// `add_class::<T>()`, `add("name", ...)` with a string literal name and
+/// `add_function(wrap_pyfunction!(...))` calls are tracked, and a module-level
+/// `__all__` list is set at the end of the body (ahead of a trailing tail expression). Other
+/// forms of registration are not tracked.
+///
+/// `default_options` are the `#[pymodule]`-level default `PyFunctionOptions` (e.g. a shared
+/// text signature or name); they are merged into every extracted `#[pyfn]`, with options set
+/// directly on the `#[pyfn]` taking precedence. Only `pass_module`, `name` and
+/// `text_signature` are merged; there is no error-conversion option among them.
+pub fn process_functions_in_module(
+    module_name: &str,
+    auto_all: bool,
+    default_options: &PyFunctionOptions,
+    func: &mut syn::ItemFn,
+) -> syn::Result<()> {
     let mut stmts: Vec<syn::Stmt> = Vec::new();
+    let module_var = module_ident(&func.sig)?;
+    let mut registered_names: Vec<TokenStream> = Vec::new();
 
     for stmt in func.block.stmts.iter_mut() {
         if let syn::Stmt::Item(syn::Item::Fn(func)) = stmt {
             if let Some(pyfn_args) = get_pyfn_attr(&mut func.attrs)? {
                 let module_name = pyfn_args.modname;
-                let (ident, wrapped_function) = impl_wrap_pyfunction(func, pyfn_args.options)?;
+                let options = default_options.clone().merge(pyfn_args.options);
+                // The exposed Python name may differ from the Rust identifier once a
+                // `#[pyo3(name = "...")]` override (possibly coming from `default_options`) is
+                // merged in, so read it back off the merged options rather than the ident.
+                let exposed_name = options
+                    .name
+                    .as_ref()
+                    .map(|name| path_to_name(&name.0))
+                    .unwrap_or_else(|| func.sig.ident.to_string());
+                let (ident, wrapped_function) = impl_wrap_pyfunction(func, options)?;
                 let item: syn::ItemFn = syn::parse_quote! {
                     fn block_wrapper() {
                         #wrapped_function
@@ -48,15 +133,149 @@ pub fn process_functions_in_module(func: &mut syn::ItemFn) -> syn::Result<()> {
                     }
                 };
                 stmts.extend(item.block.stmts.into_iter());
+                if auto_all {
+                    registered_names.push(quote!(#exposed_name));
+                }
+            } else if take_pyo3_submodule_attr(&mut func.attrs)? {
+                let submodule_fn = func.sig.ident.clone();
+                let submodule_name = submodule_fn.to_string();
+                let dotted_name = format!("{}.{}", module_name, submodule_name);
+                process_functions_in_module(&dotted_name, auto_all, default_options, func)?;
+                let item: syn::ItemFn = syn::parse_quote! {
+                    fn block_wrapper() {
+                        #func
+                        let child_module = pyo3::types::PyModule::new(#module_var.py(), #submodule_name)?;
+                        #submodule_fn(#module_var.py(), child_module)?;
+                        #module_var.add_submodule(child_module)?;
+                        #module_var
+                            .py()
+                            .import("sys")?
+                            .getattr("modules")?
+                            .set_item(#dotted_name, child_module)?;
+                    }
+                };
+                stmts.extend(item.block.stmts.into_iter());
+                if auto_all {
+                    registered_names.push(quote!(#submodule_name));
+                }
+                continue;
+            }
+        } else if auto_all {
+            if let Some((name, replacement)) = auto_all_registration(stmt) {
+                registered_names.push(name);
+                stmts.extend(replacement);
+                continue;
             }
         };
         stmts.push(stmt.clone());
     }
 
+    if auto_all {
+        let all_item: syn::Stmt = syn::parse_quote! {
+            #module_var.setattr("__all__", pyo3::types::PyList::new(#module_var.py(), &[#(#registered_names),*]))?;
+        };
+        // A `#[pymodule]` body conventionally ends in a tail expression (`Ok(())` with no
+        // semicolon) rather than a `return`. Appending after it would put a statement after a
+        // tail expression with no separating token, which doesn't parse. Insert ahead of that
+        // tail expression instead; for a body that ends in an ordinary (semicolon-terminated)
+        // statement, appending at the end is fine.
+        if matches!(stmts.last(), Some(syn::Stmt::Expr(_))) {
+            let tail = stmts.pop().unwrap();
+            stmts.push(all_item);
+            stmts.push(tail);
+        } else {
+            stmts.push(all_item);
+        }
+    }
+
     func.block.stmts = stmts;
     Ok(())
 }
 
+/// Returns the final path segment of `path` as a string, e.g. `"Foo"` for `some::Foo`.
+fn path_to_name(path: &syn::Path) -> String {
+    path.segments
+        .last()
+        .map(|segment| segment.ident.to_string())
+        .unwrap_or_default()
+}
+
+/// Recognises `#module.add_class::<T>()`, `#module.add(name, ...)` and
+/// `#module.add_function(wrap_pyfunction!(ident, ...))` calls in a plain module-body statement
+/// for `auto_all`.
+///
+/// Returns the expression that evaluates to the name actually bound in the Python module,
+/// together with the statement(s) that should replace `stmt` in the generated code (this is
+/// `stmt` unchanged, except for `add_function`, which is rewritten to bind the registered
+/// function object so its real `__name__` can be read back — it may differ from the Rust
+/// identifier passed to `wrap_pyfunction!` if the function was renamed via
+/// `#[pyfunction(name = "...")]`).
+fn auto_all_registration(stmt: &syn::Stmt) -> Option<(TokenStream, Vec<syn::Stmt>)> {
+    let expr = match stmt {
+        syn::Stmt::Semi(expr, _) | syn::Stmt::Expr(expr) => expr,
+        _ => return None,
+    };
+    let call = match expr {
+        syn::Expr::Try(try_expr) => match &*try_expr.expr {
+            syn::Expr::MethodCall(call) => call,
+            _ => return None,
+        },
+        syn::Expr::MethodCall(call) => call,
+        _ => return None,
+    };
+
+    match call.method.to_string().as_str() {
+        "add_class" => {
+            let turbofish = call.turbofish.as_ref()?;
+            match turbofish.args.first()? {
+                // `PyTypeInfo::NAME` is the type's actual Python-exposed name, honouring any
+                // `#[pyclass(name = "...")]` override, unlike the bare Rust identifier.
+                syn::GenericMethodArgument::Type(ty) => {
+                    Some((quote!(<#ty as pyo3::PyTypeInfo>::NAME), vec![stmt.clone()]))
+                }
+                _ => None,
+            }
+        }
+        "add" => match call.args.first()? {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(name),
+                ..
+            }) => Some((quote!(#name), vec![stmt.clone()])),
+            _ => None,
+        },
+        "add_function" => {
+            let arg = match call.args.first()? {
+                syn::Expr::Try(try_expr) => &*try_expr.expr,
+                other => other,
+            };
+            let mac = match arg {
+                syn::Expr::Macro(syn::ExprMacro { mac, .. }) => mac,
+                _ => return None,
+            };
+            if !mac.path.is_ident("wrap_pyfunction") {
+                return None;
+            }
+            let receiver = &*call.receiver;
+            let tmp = Ident::new("__pyo3_auto_all_fn", Span::call_site());
+            let item: syn::ItemFn = syn::parse_quote! {
+                fn block_wrapper() {
+                    let #tmp = #mac?;
+                    #receiver.add_function(#tmp)?;
+                }
+            };
+            let replacement = item.block.stmts;
+            // Read the name back from the bound function object instead of the Rust
+            // identifier passed to `wrap_pyfunction!`, since `#[pyfunction(name = "...")]`
+            // may have renamed it.
+            Some((
+                quote!(#tmp.getattr("__name__")?.extract::<&str>()?),
+                replacement,
+            ))
+        }
+        _ => None,
+    }
+}
+
 pub struct PyFnArgs {
     modname: Path,
     options: PyFunctionOptions,
@@ -120,3 +339,226 @@ fn get_pyfn_attr(attrs: &mut Vec<syn::Attribute>) -> syn::Result<Option<PyFnArgs
 
     Ok(pyfn_args)
 }
+
+impl PyFunctionOptions {
+    /// Combines `#[pymodule]`-level default options with a specific `#[pyfn]`'s own options.
+    ///
+    /// `self` is treated as the module-wide defaults and `other` as the per-function overrides;
+    /// any field `other` sets explicitly wins, and `self`'s value is only kept where `other`
+    /// left that field unset. Deprecations are the exception: only `other`'s are kept, and
+    /// any recorded on `self` are dropped.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            pass_module: other.pass_module || self.pass_module,
+            name: other.name.or(self.name),
+            text_signature: other.text_signature.or(self.text_signature),
+            deprecations: other.deprecations,
+        }
+    }
+}
+
+/// Returns the identifier of the `&PyModule` argument of a `#[pymodule]` function, i.e. the
+/// second parameter (the first being `Python`).
+fn module_ident(sig: &syn::Signature) -> syn::Result<Ident> {
+    match sig.inputs.iter().nth(1) {
+        Some(syn::FnArg::Typed(syn::PatType { pat, .. })) => match &**pat {
+            syn::Pat::Ident(syn::PatIdent { ident, .. }) => Ok(ident.clone()),
+            other => Err(
+                err_spanned!(other.span() => "expected a single identifier for the module argument"),
+            ),
+        },
+        _ => Err(
+            err_spanned!(sig.span() => "expected #[pymodule] function to take a module argument"),
+        ),
+    }
+}
+
+/// Looks for and removes a `#[pyo3(submodule)]` attribute, marking a nested `#[pymodule]`
+/// function as a submodule of the enclosing module.
+fn take_pyo3_submodule_attr(attrs: &mut Vec<syn::Attribute>) -> syn::Result<bool> {
+    let mut is_submodule = false;
+
+    take_attributes(attrs, |attr| {
+        if is_attribute_ident(attr, "pyo3") {
+            attr.parse_args_with(|input: syn::parse::ParseStream| {
+                let path: syn::Path = input.parse()?;
+                if path.is_ident("submodule") {
+                    is_submodule = true;
+                    Ok(())
+                } else {
+                    Err(err_spanned!(path.span() => "expected `submodule`"))
+                }
+            })?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    })?;
+
+    Ok(is_submodule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn py_init_multi_phase_uses_py_mod_exec_slot() {
+        let fnname: Ident = syn::parse_quote!(pymodule_function);
+        let name: Ident = syn::parse_quote!(my_module);
+        let doc: syn::LitStr = syn::parse_quote!("doc\0");
+
+        let generated = py_init(&fnname, &name, doc, true).to_string();
+
+        assert!(generated.contains("Py_mod_exec"));
+        assert!(generated.contains("PyModuleDef_Init"));
+        assert!(!generated.contains("make_module"));
+    }
+
+    #[test]
+    fn py_init_single_phase_still_calls_make_module() {
+        let fnname: Ident = syn::parse_quote!(pymodule_function);
+        let name: Ident = syn::parse_quote!(my_module);
+        let doc: syn::LitStr = syn::parse_quote!("doc\0");
+
+        let generated = py_init(&fnname, &name, doc, false).to_string();
+
+        assert!(generated.contains("make_module"));
+        assert!(!generated.contains("Py_mod_exec"));
+    }
+
+    #[test]
+    fn submodule_attribute_registers_child_module() {
+        let mut func: syn::ItemFn = syn::parse_quote! {
+            fn parent(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                #[pyo3(submodule)]
+                fn child(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                    Ok(())
+                }
+                Ok(())
+            }
+        };
+
+        process_functions_in_module("parent", false, &PyFunctionOptions::default(), &mut func)
+            .unwrap();
+
+        let func_tokens = quote!(#func);
+        syn::parse2::<syn::ItemFn>(func_tokens.clone())
+            .expect("generated module function must parse");
+        let generated = func_tokens.to_string();
+        assert!(generated.contains("add_submodule"));
+        assert!(generated.contains("\"parent.child\""));
+        assert!(generated.contains("\"sys\""));
+    }
+
+    #[test]
+    fn auto_all_includes_add_function_registrations() {
+        let mut func: syn::ItemFn = syn::parse_quote! {
+            fn my_module(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                #[pyfn(m)]
+                fn pyfn_func() {}
+
+                m.add_function(wrap_pyfunction!(plain_func, m)?)?;
+                Ok(())
+            }
+        };
+
+        process_functions_in_module("my_module", true, &PyFunctionOptions::default(), &mut func)
+            .unwrap();
+
+        // The tail expression is `Ok(())` with no semicolon; if `__all__` were appended after it
+        // this would fail to parse as valid Rust.
+        let func_tokens = quote!(#func);
+        syn::parse2::<syn::ItemFn>(func_tokens.clone())
+            .expect("generated module function must parse");
+
+        let generated = func_tokens.to_string();
+        assert!(generated.contains("__all__"));
+        assert!(generated.contains("\"pyfn_func\""));
+        // The real name for a plain `add_function(wrap_pyfunction!(...))` call is read back from
+        // the bound function object at runtime rather than guessed from the Rust identifier.
+        assert!(generated.contains("__name__"));
+        assert!(generated.contains("wrap_pyfunction"));
+    }
+
+    #[test]
+    fn auto_all_inserts_before_trailing_tail_expression() {
+        let mut func: syn::ItemFn = syn::parse_quote! {
+            fn my_module(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                #[pyfn(m)]
+                fn pyfn_func() {}
+
+                Ok(())
+            }
+        };
+
+        process_functions_in_module("my_module", true, &PyFunctionOptions::default(), &mut func)
+            .unwrap();
+
+        let parsed: syn::ItemFn =
+            syn::parse2(quote!(#func)).expect("generated module function must parse");
+        let last_stmt = parsed.block.stmts.last().expect("body must not be empty");
+        assert!(matches!(last_stmt, syn::Stmt::Expr(_)));
+    }
+
+    #[test]
+    fn auto_all_uses_merged_name_for_renamed_pyfn() {
+        let mut func: syn::ItemFn = syn::parse_quote! {
+            fn my_module(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                #[pyfn(m)]
+                #[pyo3(name = "renamed")]
+                fn original_name() {}
+
+                Ok(())
+            }
+        };
+
+        process_functions_in_module("my_module", true, &PyFunctionOptions::default(), &mut func)
+            .unwrap();
+
+        let generated = quote!(#func).to_string();
+        assert!(generated.contains("\"renamed\""));
+        assert!(!generated.contains("\"original_name\""));
+    }
+
+    #[test]
+    fn auto_all_uses_pytypeinfo_name_for_add_class() {
+        let mut func: syn::ItemFn = syn::parse_quote! {
+            fn my_module(_py: pyo3::Python, m: &pyo3::types::PyModule) -> pyo3::PyResult<()> {
+                m.add_class::<MyClass>()?;
+                Ok(())
+            }
+        };
+
+        process_functions_in_module("my_module", true, &PyFunctionOptions::default(), &mut func)
+            .unwrap();
+
+        let generated = quote!(#func).to_string();
+        assert!(generated.contains("PyTypeInfo"));
+        assert!(generated.contains("MyClass"));
+        assert!(generated.contains("NAME"));
+    }
+
+    #[test]
+    fn merge_prefers_callee_option_over_default() {
+        let mut defaults = PyFunctionOptions::default();
+        defaults.name = Some(NameAttribute(syn::parse_quote!(default_name)));
+
+        let mut callee = PyFunctionOptions::default();
+        callee.name = Some(NameAttribute(syn::parse_quote!(callee_name)));
+
+        let merged = defaults.merge(callee);
+
+        assert_eq!(merged.name.unwrap().0, syn::parse_quote!(callee_name));
+    }
+
+    #[test]
+    fn merge_falls_back_to_default_when_callee_unset() {
+        let mut defaults = PyFunctionOptions::default();
+        defaults.name = Some(NameAttribute(syn::parse_quote!(default_name)));
+
+        let merged = defaults.merge(PyFunctionOptions::default());
+
+        assert_eq!(merged.name.unwrap().0, syn::parse_quote!(default_name));
+    }
+}